@@ -0,0 +1,41 @@
+/// Guess a MIME-ish content type from the leading bytes of some data, for
+/// objects that didn't get an explicit content type. Shared by `Output` and
+/// `DataBuilder` so the two crates can't drift on which signatures they know.
+pub fn sniff_content_type(bytes: &[u8]) -> &'static str {
+    if bytes.is_empty() {
+        "application/octet-stream"
+    } else if bytes.starts_with(&[0x1f, 0x8b]) {
+        "application/gzip"
+    } else if bytes.starts_with(b"PK\x03\x04") || bytes.starts_with(b"PK\x05\x06") {
+        "application/zip"
+    } else if bytes.len() >= 262 && &bytes[257..262] == b"ustar" {
+        "application/x-tar"
+    } else if bytes.starts_with(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']) {
+        "image/png"
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        "image/jpeg"
+    } else if ::std::str::from_utf8(bytes).is_ok() {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_content_type_detects_known_signatures() {
+        assert_eq!(sniff_content_type(&[]), "application/octet-stream");
+        assert_eq!(sniff_content_type(&[0x1f, 0x8b, 0x08]), "application/gzip");
+        assert_eq!(sniff_content_type(b"PK\x03\x04rest"), "application/zip");
+        assert_eq!(
+            sniff_content_type(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']),
+            "image/png",
+        );
+        assert_eq!(sniff_content_type(&[0xFF, 0xD8, 0xFF, 0xE0]), "image/jpeg");
+        assert_eq!(sniff_content_type(b"hello world"), "text/plain");
+        assert_eq!(sniff_content_type(&[0, 159, 146, 150]), "application/octet-stream");
+    }
+}
@@ -0,0 +1,68 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc;
+
+/// Pick the directory from `dirs` with the most free space, breaking ties
+/// round-robin via `round_robin` so equally-free disks aren't hammered one
+/// at a time. Shared by `WorkDir` and `Output`, which each spread their own
+/// large spills across multiple candidate directories the same way.
+pub fn pick_dir<'d>(dirs: &'d [PathBuf], round_robin: &AtomicUsize) -> io::Result<&'d Path> {
+    if dirs.len() == 1 {
+        return Ok(&dirs[0]);
+    }
+    let free: Vec<u64> = dirs.iter().map(|d| free_space(d)).collect::<io::Result<_>>()?;
+    let max_free = *free.iter().max().unwrap();
+    let candidates: Vec<usize> = free.iter().enumerate()
+        .filter(|&(_, &f)| f == max_free)
+        .map(|(i, _)| i)
+        .collect();
+    let next = round_robin.fetch_add(1, Ordering::Relaxed);
+    Ok(&dirs[candidates[next % candidates.len()]])
+}
+
+/// Free space available on the filesystem backing `path`, in bytes.
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+    let mut buf: libc::statvfs = unsafe { ::std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut buf) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(buf.f_bavail as u64 * buf.f_frsize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_dir_round_robins_on_equal_free_space() {
+        // `dir` and `sub` are on the same filesystem, so they tie on free
+        // space; pick_dir must alternate between them rather than always
+        // returning the first.
+        let dir = ::std::env::temp_dir();
+        let sub = dir.join(format!("rain-test-dir-pick-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&sub).unwrap();
+        let dirs = vec![dir.clone(), sub.clone()];
+        let round_robin = AtomicUsize::new(0);
+        let first = pick_dir(&dirs, &round_robin).unwrap().to_path_buf();
+        let second = pick_dir(&dirs, &round_robin).unwrap().to_path_buf();
+        let third = pick_dir(&dirs, &round_robin).unwrap().to_path_buf();
+        assert_eq!(first, dir);
+        assert_eq!(second, sub);
+        assert_eq!(third, dir);
+        ::std::fs::remove_dir_all(&sub).unwrap();
+    }
+
+    #[test]
+    fn pick_dir_single_candidate_skips_round_robin() {
+        let dirs = vec![::std::env::temp_dir()];
+        let round_robin = AtomicUsize::new(0);
+        pick_dir(&dirs, &round_robin).unwrap();
+        assert_eq!(round_robin.load(Ordering::Relaxed), 0);
+    }
+}
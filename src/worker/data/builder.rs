@@ -1,22 +1,35 @@
 use std::fs::File;
+use std::io::Read;
+use base64;
+use sha2::{Digest, Sha256};
 use super::data::{Data, Storage};
 use errors::Result;
 use super::super::fs::workdir::WorkDir;
-use common::DataType;
+use common::{Attributes, DataType};
+use common::content_type::sniff_content_type;
 use worker::fs::tempfile::TempFileName;
 
+/// Size of the chunks used to stream-copy a blob when its source cannot be
+/// safely `mmap`ed (e.g. it lives on a network filesystem).
+const STREAM_COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
 enum BuilderStorage {
     Memory(Vec<u8>),
     File((File, TempFileName))
 }
 
-pub struct DataBuilder {
+pub struct DataBuilder<'a> {
     storage: BuilderStorage,
     data_type: DataType,
+    /// Streaming digest of everything passed to `write` so far.
+    hasher: Sha256,
+    workdir: &'a WorkDir,
+    /// Content type explicitly set via `set_content_type`, if any.
+    content_type: Option<String>,
 }
 
-impl DataBuilder {
-    pub fn new(workdir: &WorkDir, data_type: DataType, expected_size: Option<usize>) -> Self {
+impl<'a> DataBuilder<'a> {
+    pub fn new(workdir: &'a WorkDir, data_type: DataType, expected_size: Option<usize>) -> Self {
 
         fn file_storage(workdir: &WorkDir) -> BuilderStorage {
             let f = workdir.make_temp_file();
@@ -32,7 +45,7 @@ impl DataBuilder {
         } else {
             file_storage(workdir)
         };
-        DataBuilder { data_type, storage }
+        DataBuilder { data_type, storage, hasher: Sha256::new(), workdir, content_type: None }
     }
 
     // TODO: Get rid of this method
@@ -41,26 +54,109 @@ impl DataBuilder {
         match data.storage() {
             &Storage::Memory(ref bytes) => self.write(&bytes[..]),
             &Storage::Path(ref path) => {
-                let mem = unsafe { ::memmap::Mmap::map(&File::open(&path.path)?) }?;
-                self.write(&mem);
+                // mmap is unsafe to rely on over the network: a remote
+                // truncation or unmount can SIGBUS us mid-read. Stream
+                // through a plain buffered copy in that case instead.
+                if self.workdir.is_network_filesystem_at(&path.path)? {
+                    self.stream_copy_blob(&path.path)?;
+                } else {
+                    let mem = unsafe { ::memmap::Mmap::map(&File::open(&path.path)?) }?;
+                    self.write(&mem);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn stream_copy_blob(&mut self, path: &::std::path::Path) -> Result<()> {
+        let mut f = File::open(path)?;
+        let mut buf = [0u8; STREAM_COPY_CHUNK_SIZE];
+        loop {
+            let read = f.read(&mut buf)?;
+            if read == 0 {
+                break;
             }
+            self.write(&buf[..read]);
         }
         Ok(())
     }
 
     pub fn write(&mut self, data: &[u8]) {
+        self.hasher.input(data);
         match self.storage {
             BuilderStorage::Memory(ref mut buffer) => buffer.extend_from_slice(data),
             BuilderStorage::File(ref file, _) => file.write_all(data),
         }
     }
 
-    pub fn build(&mut self) -> Data {
+    /// Finalize the streaming hash into a self-describing integrity string
+    /// of the form `"sha256-<base64(digest)>"`, suitable for storing in the
+    /// built object's `Attributes` (it round-trips through `DataObjectSpec`).
+    /// An object that had nothing written to it still yields the digest of
+    /// zero bytes. Does not consume the builder, so it may be called right
+    /// before (or after) `build()`; `build()` already attaches this under
+    /// `"integrity"` for you.
+    pub fn digest(&self) -> String {
+        format!("sha256-{}", base64::encode(&self.hasher.clone().result()))
+    }
+
+    /// Explicitly record this object's content type, overriding the
+    /// automatic sniffing `content_type` would otherwise perform.
+    pub fn set_content_type(&mut self, content_type: String) {
+        self.content_type = Some(content_type);
+    }
+
+    /// The content type explicitly set via `set_content_type`, or, if none
+    /// was set, one guessed from the bytes written so far for a memory-backed
+    /// builder; a file-backed builder is not sniffed and reports
+    /// `application/octet-stream`.
+    pub fn content_type(&self) -> &str {
+        if let Some(ref content_type) = self.content_type {
+            return content_type;
+        }
         match self.storage {
+            BuilderStorage::Memory(ref buffer) => sniff_content_type(buffer),
+            // A file-backed builder isn't sniffed; callers that care about
+            // the content type of large/streamed data should set it explicitly.
+            BuilderStorage::File(..) => "application/octet-stream",
+        }
+    }
+
+    /// Consume the accumulated bytes into a `Data` and the `Attributes`
+    /// finalized alongside it: `digest()` under `"integrity"`, and
+    /// `content_type()` under `"content_type"`.
+    pub fn build(&mut self) -> (Data, Attributes) {
+        let mut attributes = Attributes::new();
+        let _ = attributes.set("integrity", self.digest());
+        let _ = attributes.set("content_type", self.content_type());
+        let data = match self.storage {
             BuilderStorage::Memory(ref mut buffer) => Data::new(
                 Storage::Memory(::std::mem::replace(buffer, Vec::new())),
                 self.data_type,
             )
-        }
+        };
+        (data, attributes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_of_empty_input_is_sha256_of_empty_string() {
+        let workdir = WorkDir::new(vec![::std::env::temp_dir()]);
+        let builder = DataBuilder::new(&workdir, DataType::Blob, Some(0));
+        assert_eq!(builder.digest(), "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
+    }
+
+    #[test]
+    fn content_type_prefers_explicit_over_sniffed() {
+        let workdir = WorkDir::new(vec![::std::env::temp_dir()]);
+        let mut builder = DataBuilder::new(&workdir, DataType::Blob, Some(16));
+        builder.write(b"hello world");
+        assert_eq!(builder.content_type(), "text/plain");
+        builder.set_content_type("application/x-custom".to_string());
+        assert_eq!(builder.content_type(), "application/x-custom");
     }
 }
@@ -0,0 +1,26 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use errors::Result;
+
+/// A freshly created, uniquely named temporary file under a data directory.
+/// Keeps the path around so callers can reopen or rename it.
+#[derive(Debug)]
+pub struct TempFileName {
+    path: PathBuf,
+}
+
+impl TempFileName {
+    /// Create a new empty temp file directly under `dir`.
+    pub fn new_in(dir: &Path) -> Result<Self> {
+        let path = dir.join(format!("tmp-{}", Uuid::new_v4()));
+        File::create(&path)?;
+        Ok(TempFileName { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
@@ -0,0 +1,165 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicUsize;
+
+use libc;
+
+use common::dir_pick;
+use errors::Result;
+use worker::fs::tempfile::TempFileName;
+
+/// `f_type` magics (from `statfs(2)`) of filesystems where `mmap`ing a file
+/// is unsafe to rely on: a remote truncation or unmount can `SIGBUS` us.
+const NETWORK_FS_MAGICS: &[i64] = &[
+    0x6969,               // NFS_SUPER_MAGIC
+    0xFF534D42u32 as i64, // CIFS/SMB
+    0x65735546,           // FUSE_SUPER_MAGIC
+    0x01021997,           // V9FS_MAGIC (9P)
+];
+
+/// The directory (or directories) a worker uses to stage temporary and
+/// output data for a task.
+///
+/// When several data directories are given (e.g. several mounted disks),
+/// large spills are spread across them by free space instead of always
+/// filling the first one. Repeated filesystem-kind checks are cached since
+/// `statfs` is not free.
+pub struct WorkDir {
+    dirs: Vec<PathBuf>,
+    round_robin: AtomicUsize,
+    network_fs_cache: RefCell<HashMap<PathBuf, bool>>,
+}
+
+impl WorkDir {
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        assert!(!dirs.is_empty(), "WorkDir requires at least one data directory");
+        WorkDir {
+            dirs,
+            round_robin: AtomicUsize::new(0),
+            network_fs_cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// The primary data directory (the first configured one).
+    pub fn path(&self) -> &Path {
+        &self.dirs[0]
+    }
+
+    pub fn dirs(&self) -> &[PathBuf] {
+        &self.dirs
+    }
+
+    /// Pick the data directory with the most free space, breaking ties
+    /// round-robin so that equally-free disks aren't hammered one at a time.
+    pub fn pick_data_dir(&self) -> Result<&Path> {
+        Ok(dir_pick::pick_dir(&self.dirs, &self.round_robin)?)
+    }
+
+    /// Create a new temp file in whichever data directory currently has the
+    /// most free space.
+    pub fn make_temp_file(&self) -> TempFileName {
+        let dir = self.pick_data_dir().unwrap();
+        TempFileName::new_in(dir).unwrap()
+    }
+
+    /// Whether this work directory's primary data directory is backed by a
+    /// network filesystem (NFS, SMB/CIFS, FUSE, 9P), where `mmap` is not
+    /// safe to use.
+    pub fn is_network_filesystem(&self) -> Result<bool> {
+        self.is_network_filesystem_at(&self.dirs[0])
+    }
+
+    /// Same as `is_network_filesystem`, but checks the filesystem backing an
+    /// arbitrary `path` (e.g. the source of a blob being copied in), not just
+    /// a directory of this work dir. The answer for a file is cached per
+    /// containing directory (every file under the same directory shares a
+    /// filesystem), so repeated blob writes from the same mount don't
+    /// re-`statfs` even though each blob has its own distinct full path.
+    /// A directory (which may itself be a mount point) is cached under itself.
+    pub fn is_network_filesystem_at(&self, path: &Path) -> Result<bool> {
+        let key = if path.is_dir() {
+            path.to_path_buf()
+        } else {
+            path.parent().unwrap_or(path).to_path_buf()
+        };
+        if let Some(&cached) = self.network_fs_cache.borrow().get(&key) {
+            return Ok(cached);
+        }
+        let result = is_network_fs_magic(statfs_type(path)?);
+        self.network_fs_cache.borrow_mut().insert(key, result);
+        Ok(result)
+    }
+}
+
+fn statfs_type(path: &Path) -> Result<i64> {
+    let file = File::open(path)?;
+    let mut buf: libc::statfs = unsafe { ::std::mem::zeroed() };
+    let rc = unsafe { libc::fstatfs(file.as_raw_fd(), &mut buf) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(buf.f_type as i64)
+}
+
+fn is_network_fs_magic(f_type: i64) -> bool {
+    NETWORK_FS_MAGICS.contains(&f_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_network_fs_magic_recognizes_known_network_filesystems() {
+        assert!(is_network_fs_magic(0x6969));
+        assert!(is_network_fs_magic(0xFF534D42u32 as i64));
+        assert!(is_network_fs_magic(0x65735546));
+        assert!(is_network_fs_magic(0x01021997));
+    }
+
+    #[test]
+    fn is_network_fs_magic_rejects_local_filesystems() {
+        assert!(!is_network_fs_magic(0xEF53)); // EXT4_SUPER_MAGIC
+        assert!(!is_network_fs_magic(0x9123683E)); // BTRFS_SUPER_MAGIC
+    }
+
+    #[test]
+    fn is_network_filesystem_at_caches_by_containing_directory() {
+        let workdir = WorkDir::new(vec![::std::env::temp_dir()]);
+        let dir = ::std::env::temp_dir();
+        let a = dir.join(format!("rain-test-cache-a-{}", ::std::process::id()));
+        let b = dir.join(format!("rain-test-cache-b-{}", ::std::process::id()));
+        File::create(&a).unwrap();
+        File::create(&b).unwrap();
+        // Both files live directly in `dir`, so the second lookup must hit
+        // the cache keyed by `dir` rather than re-`statfs`ing; if it didn't,
+        // this would still pass functionally, but the point of the cache is
+        // that only one entry is ever inserted for both.
+        let first = workdir.is_network_filesystem_at(&a).unwrap();
+        let second = workdir.is_network_filesystem_at(&b).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(workdir.network_fs_cache.borrow().len(), 1);
+        ::std::fs::remove_file(&a).unwrap();
+        ::std::fs::remove_file(&b).unwrap();
+    }
+
+    #[test]
+    fn pick_data_dir_round_robins_on_equal_free_space() {
+        // `dir` and `sub` are on the same filesystem, so they tie on free
+        // space; pick_data_dir must alternate between them rather than
+        // always returning the first.
+        let dir = ::std::env::temp_dir();
+        let sub = dir.join(format!("rain-test-workdir-pick-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&sub).unwrap();
+        let workdir = WorkDir::new(vec![dir.clone(), sub.clone()]);
+        let first = workdir.pick_data_dir().unwrap().to_path_buf();
+        let second = workdir.pick_data_dir().unwrap().to_path_buf();
+        assert_eq!(first, dir);
+        assert_eq!(second, sub);
+        ::std::fs::remove_dir_all(&sub).unwrap();
+    }
+}